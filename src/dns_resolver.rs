@@ -1,41 +1,143 @@
+use crate::dnssec;
+use lru::LruCache;
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 use std::net::IpAddr;
-use std::sync::{Arc, RwLock};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, RecordType};
+use trust_dns_proto::serialize::binary::BinEncodable;
 use trust_dns_resolver::config::*;
 use trust_dns_resolver::TokioAsyncResolver;
 
+static DOH_QUERY_ID: AtomicU16 = AtomicU16::new(0);
+
+// Cache entries beyond this are evicted least-recently-used first so the
+// cache cannot grow without bound.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
 pub struct DNSRecord {
     ip: Vec<IpAddr>,
     valid_until: Instant,
+    dnssec_validated: bool,
+}
+
+// Drives the singleflight protocol for one in-flight resolution: once the
+// lookup finishes (successfully or not) the leader calls `complete` to remove
+// the shared entry and broadcast the result to every waiter. If the leader future is instead dropped/cancelled
+// beforehand (e.g. by a caller-side timeout), Drop removes the entry anyway
+// so waiters see their sender close rather than waiting forever on a result
+// that will never arrive.
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, Vec<oneshot::Sender<SingleflightResult>>>>,
+    domain: String,
+    completed: bool,
 }
 
+impl<'a> InFlightGuard<'a> {
+    fn new(in_flight: &'a Mutex<HashMap<String, Vec<oneshot::Sender<SingleflightResult>>>>, domain: String) -> Self {
+        Self {
+            in_flight,
+            domain,
+            completed: false,
+        }
+    }
+
+    fn complete(mut self, result: SingleflightResult) {
+        self.completed = true;
+        let waiters = self.in_flight.lock().unwrap().remove(&self.domain).unwrap_or_default();
+        for tx in waiters {
+            let _ = tx.send(result.clone());
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = self.in_flight.lock().unwrap().remove(&self.domain);
+        }
+    }
+}
+
+// Selects where DNSResolver sends lookups: the system/default resolver, or a
+// DNS-over-HTTPS endpoint that answers "application/dns-message" POSTs.
+#[derive(Clone)]
+pub enum DnsUpstream {
+    System,
+    DoH(String),
+}
+
+// Result broadcast to singleflight waiters once the in-flight resolution
+// completes. io::Error isn't Clone, so errors travel as a String.
+type SingleflightResult = std::result::Result<Vec<IpAddr>, String>;
+
 #[derive(Clone)]
 pub struct DNSResolver {
-    records: Arc<RwLock<HashMap<String, DNSRecord>>>,
+    records: Arc<RwLock<LruCache<String, DNSRecord>>>,
     ttl: Duration,
     tokio_resolver: TokioAsyncResolver,
+    upstream: DnsUpstream,
+    http_client: reqwest::Client,
+    in_flight: Arc<Mutex<HashMap<String, Vec<oneshot::Sender<SingleflightResult>>>>>,
+    validate_dnssec: bool,
 }
 
 impl DNSResolver {
     pub fn new(ttl: u64) -> Self {
+        Self::new_with_upstream(ttl, DnsUpstream::System)
+    }
+
+    pub fn new_with_upstream(ttl: u64, upstream: DnsUpstream) -> Self {
+        Self::new_with_capacity(ttl, upstream, DEFAULT_CACHE_CAPACITY)
+    }
+
+    pub fn new_with_capacity(ttl: u64, upstream: DnsUpstream, capacity: usize) -> Self {
+        Self::new_full(ttl, upstream, capacity, false)
+    }
+
+    // Opt-in DNSSEC validation: answers are only cached once the chain of
+    // trust back to the hard-coded root anchor has been verified.
+    pub fn new_with_dnssec(ttl: u64, upstream: DnsUpstream) -> Self {
+        Self::new_full(ttl, upstream, DEFAULT_CACHE_CAPACITY, true)
+    }
+
+    fn new_full(ttl: u64, upstream: DnsUpstream, capacity: usize, validate_dnssec: bool) -> Self {
         // TokioAsyncResolver::tokio_from_system_conf().await.unwrap() // uses system DNS resolver instead of default
+        let mut resolver_opts = ResolverOpts::default();
+        if validate_dnssec {
+            // DNSSEC validation needs RRSIG/DNSKEY/DS records in the
+            // answer, which most upstreams only include when the DO bit is
+            // set on an EDNS0 OPT record. Without this, `dnssec::validate`
+            // finds nothing to verify and fails closed on every lookup.
+            resolver_opts.edns0 = true;
+        }
+
         Self {
-            records: Arc::new(RwLock::new(HashMap::new())),
+            records: Arc::new(RwLock::new(LruCache::new(
+                NonZeroUsize::new(capacity).expect("cache capacity must be non-zero"),
+            ))),
             ttl: Duration::from_secs(ttl),
-            tokio_resolver: TokioAsyncResolver::tokio(
-                ResolverConfig::default(),
-                ResolverOpts::default(),
-            )
-            .unwrap(),
+            tokio_resolver: TokioAsyncResolver::tokio(ResolverConfig::default(), resolver_opts).unwrap(),
+            upstream,
+            http_client: reqwest::Client::new(),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            validate_dnssec,
         }
     }
 
     pub fn check_cache(&self, domain: &str) -> Option<Vec<IpAddr>> {
-        let records = self.records.read().unwrap();
+        let mut records = self.records.write().unwrap();
         if let Some(record) = records.get(domain) {
-            if record.valid_until > Instant::now() {
+            // A DNSSEC-validating resolver must never serve an entry it
+            // didn't itself validate, even if something populated the
+            // cache via the plain `update_cache` path.
+            let dnssec_ok = !self.validate_dnssec || record.dnssec_validated;
+            if record.valid_until > Instant::now() && dnssec_ok {
                 return Some(record.ip.clone());
             }
         }
@@ -44,18 +146,40 @@ impl DNSResolver {
 
     pub fn update_cache(&self, domain: &str, ip: Vec<IpAddr>) {
         let mut records = self.records.write().unwrap();
-        records.insert(
+        records.put(
             domain.to_string(),
             DNSRecord {
                 ip: ip,
                 valid_until: Instant::now() + self.ttl,
+                dnssec_validated: false,
+            },
+        );
+    }
+
+    // Cache a DNSSEC-validated answer, capping its lifetime by the earliest
+    // RRSIG expiration so a long resolver TTL can't outlive the signatures.
+    fn update_cache_validated(&self, domain: &str, ip: Vec<IpAddr>, valid_until: Instant) {
+        let mut records = self.records.write().unwrap();
+        records.put(
+            domain.to_string(),
+            DNSRecord {
+                ip,
+                valid_until: valid_until.min(Instant::now() + self.ttl),
+                dnssec_validated: true,
             },
         );
     }
 
     pub async fn cleanup_expired_records(&self) {
         let mut records = self.records.write().unwrap();
-        records.retain(|_, record| record.valid_until > Instant::now());
+        let expired: Vec<String> = records
+            .iter()
+            .filter(|(_, record)| record.valid_until <= Instant::now())
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        for domain in expired {
+            records.pop(&domain);
+        }
     }
 
     pub async fn resolve_domain(&self, domain: &str) -> Result<Vec<IpAddr>> {
@@ -66,17 +190,150 @@ impl DNSResolver {
             return Ok(ip);
         }
 
-        match self.tokio_resolver.lookup_ip(domain).await {
-            Ok(result) => {
-                let resolved_ips: Vec<IpAddr> = result.iter().map(|ip| ip.into()).collect();
-                if !resolved_ips.is_empty() {
-                    self.update_cache(domain, resolved_ips.clone());
-                }
-                Ok(resolved_ips)
+        // Singleflight: if another task is already resolving this domain,
+        // wait on its result instead of firing a duplicate upstream lookup.
+        let waiter = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(waiters) = in_flight.get_mut(domain) {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Some(rx)
+            } else {
+                in_flight.insert(domain.to_string(), Vec::new());
+                None
             }
+        };
+
+        if let Some(rx) = waiter {
+            return rx
+                .await
+                .map_err(|_| Error::new(ErrorKind::Other, "in-flight resolution was dropped"))?
+                .map_err(|err| Error::new(ErrorKind::Other, err));
+        }
+
+        // We're the leader for this domain now. The guard ensures the
+        // in-flight entry is cleaned up even if this future is cancelled
+        // before reaching `complete` below.
+        let guard = InFlightGuard::new(&self.in_flight, domain.to_string());
+
+        let mut result = match &self.upstream {
+            DnsUpstream::System => self.resolve_via_system(domain).await,
+            DnsUpstream::DoH(endpoint) => self.resolve_via_doh(domain, endpoint).await,
+        };
+
+        if self.validate_dnssec {
+            result = match result {
+                Ok(resolved_ips) => match dnssec::validate(&self.tokio_resolver, domain, &resolved_ips).await {
+                    Ok(validation) => {
+                        if !resolved_ips.is_empty() {
+                            self.update_cache_validated(domain, resolved_ips.clone(), validation.valid_until);
+                        }
+                        Ok(resolved_ips)
+                    }
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+        } else if let Ok(resolved_ips) = &result {
+            if !resolved_ips.is_empty() {
+                self.update_cache(domain, resolved_ips.clone());
+            }
+        }
+
+        let broadcast: SingleflightResult = result.as_ref().map(Clone::clone).map_err(|err| err.to_string());
+        guard.complete(broadcast);
+
+        result
+    }
+
+    async fn resolve_via_system(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        match self.tokio_resolver.lookup_ip(domain).await {
+            Ok(result) => Ok(result.iter().map(|ip| ip.into()).collect()),
             Err(err) => Err(Error::new(ErrorKind::Other, err)),
         }
     }
+
+    // Resolve `domain` by POSTing binary DNS queries (RFC 8484) to a DoH
+    // endpoint for both A and AAAA RRsets and merging the results.
+    async fn resolve_via_doh(&self, domain: &str, endpoint: &str) -> Result<Vec<IpAddr>> {
+        let (rcode, mut ips) = self.query_doh(domain, endpoint, RecordType::A).await?;
+        if rcode != ResponseCode::NoError {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("DoH query for {} returned {}", domain, rcode),
+            ));
+        }
+
+        // AAAA is best-effort: a host with no IPv6 records legitimately
+        // answers NXDOMAIN/NoError-with-no-answers here, so only the A
+        // query's RCODE is treated as fatal.
+        if let Ok((ResponseCode::NoError, mut aaaa_ips)) =
+            self.query_doh(domain, endpoint, RecordType::AAAA).await
+        {
+            ips.append(&mut aaaa_ips);
+        }
+
+        Ok(ips)
+    }
+
+    async fn query_doh(
+        &self,
+        domain: &str,
+        endpoint: &str,
+        record_type: RecordType,
+    ) -> Result<(ResponseCode, Vec<IpAddr>)> {
+        let name = Name::from_ascii(domain).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let mut query = Query::new();
+        query.set_name(name).set_query_type(record_type);
+
+        let mut message = Message::new();
+        message.set_id(DOH_QUERY_ID.fetch_add(1, Ordering::Relaxed));
+        message.set_message_type(MessageType::Query);
+        message.set_op_code(OpCode::Query);
+        message.set_recursion_desired(true);
+        message.add_query(query);
+
+        let wire = message
+            .to_bytes()
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        let response = self
+            .http_client
+            .post(endpoint)
+            .header("content-type", "application/dns-message")
+            .header("accept", "application/dns-message")
+            .body(wire)
+            .send()
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        parse_doh_response(&body)
+    }
+}
+
+// Parses a raw DoH wire response into its RCODE and any A/AAAA answers.
+// Pulled out of `query_doh` so the parsing logic can be exercised without a
+// network round-trip.
+fn parse_doh_response(body: &[u8]) -> Result<(ResponseCode, Vec<IpAddr>)> {
+    let response_message = Message::from_vec(body).map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let ips = response_message
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::A(ip)) => Some(IpAddr::V4((*ip).into())),
+            Some(RData::AAAA(ip)) => Some(IpAddr::V6((*ip).into())),
+            _ => None,
+        })
+        .collect();
+
+    Ok((response_message.response_code(), ips))
 }
 
 #[cfg(test)]
@@ -85,6 +342,43 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn parse_doh_response_surfaces_non_success_rcode() {
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        message.set_response_code(ResponseCode::NXDomain);
+        let wire = message.to_bytes().unwrap();
+
+        let (rcode, ips) = parse_doh_response(&wire).unwrap();
+        assert_eq!(rcode, ResponseCode::NXDomain);
+        assert!(ips.is_empty());
+    }
+
+    #[test]
+    fn parse_doh_response_collects_a_and_aaaa_answers() {
+        use std::net::{Ipv4Addr, Ipv6Addr};
+        use trust_dns_proto::rr::Record;
+
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        message.set_response_code(ResponseCode::NoError);
+        message.add_answer(Record::from_rdata(
+            name.clone(),
+            60,
+            RData::A(Ipv4Addr::new(93, 184, 216, 34).into()),
+        ));
+        message.add_answer(Record::from_rdata(name, 60, RData::AAAA(Ipv6Addr::LOCALHOST.into())));
+
+        let wire = message.to_bytes().unwrap();
+        let (rcode, ips) = parse_doh_response(&wire).unwrap();
+
+        assert_eq!(rcode, ResponseCode::NoError);
+        assert_eq!(ips.len(), 2);
+        assert!(ips.contains(&IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))));
+        assert!(ips.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
     #[tokio::test]
     async fn test_successful_resolution() {
         let dns_resolver = DNSResolver::new(60);
@@ -168,6 +462,62 @@ mod test {
         );
     }
 
+    // Concurrent misses for the same domain should coalesce into a single
+    // upstream lookup: every waiter gets the exact same cloned answer rather
+    // than each spawning its own independent (and possibly differently
+    // ordered) lookup.
+    #[tokio::test]
+    async fn test_singleflight_coalesces_concurrent_misses() {
+        let dns_resolver = DNSResolver::new(60);
+        let domain = "www.google.com";
+
+        let mut handles = Vec::new();
+        for _ in 0..10 {
+            let dns_resolver = dns_resolver.clone();
+            let domain = domain.to_string();
+            handles.push(tokio::spawn(async move {
+                dns_resolver.resolve_domain(&domain).await.unwrap()
+            }));
+        }
+
+        let mut ips = Vec::new();
+        for handle in handles {
+            ips.push(handle.await.unwrap());
+        }
+
+        for result in &ips[1..] {
+            assert_eq!(
+                result, &ips[0],
+                "all waiters should observe the same in-flight resolution"
+            );
+        }
+        assert_eq!(
+            dns_resolver.records.read().unwrap().len(),
+            1,
+            "singleflight should only populate a single cache entry"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction_bounds_cache_size() {
+        let dns_resolver = DNSResolver::new_with_capacity(60, DnsUpstream::System, 2);
+        let domains = vec!["www.google.com", "www.facebook.com", "www.twitter.com"];
+
+        for domain in &domains {
+            dns_resolver.resolve_domain(domain).await.unwrap();
+        }
+
+        assert_eq!(
+            dns_resolver.records.read().unwrap().len(),
+            2,
+            "cache must not grow past its configured capacity"
+        );
+        assert!(
+            dns_resolver.check_cache(domains[0]).is_none(),
+            "the least-recently-used entry should have been evicted"
+        );
+    }
+
     // test for big servers with multiple IPs
     #[tokio::test]
     async fn test_concurrent_resolutions_2() {
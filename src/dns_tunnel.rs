@@ -0,0 +1,368 @@
+// Carries a TCP byte stream over DNS queries, for networks where only DNS
+// egress is permitted. Outbound bytes are hex-encoded into query labels
+// under a delegated suffix; the server answers with queued downstream bytes
+// packed into TXT record RDATA.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Mutex};
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_server::authority::MessageResponseBuilder;
+use trust_dns_server::client::op::{Header, ResponseCode};
+use trust_dns_server::client::rr::rdata::TXT;
+use trust_dns_server::client::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+static TUNNEL_QUERY_ID: AtomicU16 = AtomicU16::new(0);
+
+// DNS label/name limits (RFC 1035).
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+// Each label carries whole hex-encoded bytes (2 hex chars per byte), so we
+// chunk the *raw* payload into MAX_LABEL_LEN/2 byte groups rather than
+// chunking the hex string directly. MAX_LABEL_LEN (63) is odd, so chunking
+// the hex string itself can flush a label mid-byte when a name fills up,
+// splitting a byte's two hex digits across two query names and leaving each
+// side with an odd-length, undecodable hex string. Byte-aligned chunks keep
+// every label -- and therefore every name, since a name is just labels
+// concatenated -- an even number of hex characters long.
+const BYTES_PER_LABEL: usize = MAX_LABEL_LEN / 2;
+
+// Per-client streams are torn down after this much inactivity.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Splits `data` into hex-encoded DNS labels no longer than MAX_LABEL_LEN,
+// prefixes the client id, and appends `suffix` so the query is routed to the
+// delegated DNS-tunnel server. Returns one or more query names; payloads that
+// don't fit in a single name under MAX_NAME_LEN are split across names.
+pub fn encode_query_names(client_id: &str, data: &[u8], suffix: &str) -> Vec<String> {
+    let label_chunks: Vec<String> = data
+        .chunks(BYTES_PER_LABEL)
+        .map(hex::encode)
+        .collect();
+
+    let mut names = Vec::new();
+    let mut current_labels: Vec<&str> = vec![client_id];
+    for label in &label_chunks {
+        let candidate_len: usize = current_labels.iter().map(|l| l.len() + 1).sum::<usize>()
+            + label.len()
+            + 1
+            + suffix.len();
+        if candidate_len > MAX_NAME_LEN {
+            names.push(format!("{}.{}", current_labels.join("."), suffix));
+            current_labels = vec![client_id];
+        }
+        current_labels.push(label);
+    }
+    names.push(format!("{}.{}", current_labels.join("."), suffix));
+    names
+}
+
+// Decodes a query name of the form `<client_id>.<hex>...<hex>.<suffix>` back
+// into (client_id, payload bytes). Returns None if the name doesn't carry a
+// decodable hex payload under the expected suffix.
+pub fn decode_query_name(name: &Name, suffix: &Name) -> Option<(String, Vec<u8>)> {
+    if !suffix.zone_of(name) {
+        return None;
+    }
+
+    let labels: Vec<String> = name
+        .iter()
+        .take(name.num_labels() as usize - suffix.num_labels() as usize)
+        .map(|l| String::from_utf8_lossy(l).to_string())
+        .collect();
+
+    let (client_id, hex_labels) = labels.split_first()?;
+    let hex_payload: String = hex_labels.concat();
+    let payload = hex::decode(hex_payload).ok()?;
+    Some((client_id.to_string(), payload))
+}
+
+// A DNS TXT character-string is length-prefixed with a single byte, capping
+// it at 255 raw bytes -- 254 hex chars once `downstream` is hex-encoded.
+// Longer payloads are split across multiple character-strings within the
+// same TXT RDATA. As with BYTES_PER_LABEL, the raw bytes are chunked before
+// hex-encoding, so every chunk's hex length stays even.
+const MAX_TXT_STRING_LEN: usize = 255;
+const BYTES_PER_TXT_STRING: usize = MAX_TXT_STRING_LEN / 2;
+
+// Hex-encodes `data` into one or more TXT character-strings, each within the
+// 255-byte character-string limit.
+fn encode_txt_strings(data: &[u8]) -> Vec<String> {
+    if data.is_empty() {
+        return vec![String::new()];
+    }
+    data.chunks(BYTES_PER_TXT_STRING).map(hex::encode).collect()
+}
+
+// Inverse of encode_txt_strings: concatenates and hex-decodes every
+// character-string in a TXT RDATA back into the original bytes.
+fn decode_txt_strings(strings: &[String]) -> Option<Vec<u8>> {
+    let mut payload = Vec::new();
+    for s in strings {
+        payload.extend(hex::decode(s).ok()?);
+    }
+    Some(payload)
+}
+
+struct ClientStream {
+    // Bytes relayed from the DNS-tunnel client toward the tunnelled TCP
+    // connection.
+    upstream_tx: mpsc::UnboundedSender<Vec<u8>>,
+    // Bytes queued for delivery back to the client inside TXT answers.
+    downstream_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    last_seen: Instant,
+}
+
+// Server-side half of the DNS tunnel: a RequestHandler that demultiplexes
+// incoming query names by client id, feeds decoded payloads into each
+// client's duplex stream, and answers with any downstream bytes queued for
+// that client. There's no separate "open connection" message in this
+// protocol -- a client id's first query both creates its stream and dials
+// `target_addr` on its behalf, bridging the two for as long as the client
+// stays active.
+pub struct DnsTunnelServer {
+    suffix: Name,
+    target_addr: SocketAddr,
+    clients: Arc<Mutex<HashMap<String, ClientStream>>>,
+}
+
+impl DnsTunnelServer {
+    pub fn new(suffix: Name, target_addr: SocketAddr) -> Self {
+        Self {
+            suffix,
+            target_addr,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Registers a new client connection and returns the channel ends used to
+    // relay bytes to/from the tunnelled TCP stream: (bytes-from-client,
+    // queue-bytes-to-client). Exposed for callers that want to drive the
+    // tunnelled stream themselves instead of the built-in TCP bridge that
+    // first-contact queries get via handle_query.
+    pub async fn register_client(
+        &self,
+        client_id: String,
+    ) -> (mpsc::UnboundedReceiver<Vec<u8>>, mpsc::UnboundedSender<Vec<u8>>) {
+        let (upstream_tx, upstream_rx) = mpsc::unbounded_channel();
+        let (downstream_tx, downstream_rx) = mpsc::unbounded_channel();
+
+        let mut clients = self.clients.lock().await;
+        clients.insert(
+            client_id,
+            ClientStream {
+                upstream_tx,
+                downstream_rx,
+                last_seen: Instant::now(),
+            },
+        );
+
+        (upstream_rx, downstream_tx)
+    }
+
+    async fn handle_query(&self, name: &Name) -> Vec<u8> {
+        let Some((client_id, payload)) = decode_query_name(name, &self.suffix) else {
+            return Vec::new();
+        };
+
+        let mut clients = self.clients.lock().await;
+        clients.retain(|_, client| client.last_seen.elapsed() < CLIENT_IDLE_TIMEOUT);
+
+        if !clients.contains_key(&client_id) {
+            let (upstream_tx, upstream_rx) = mpsc::unbounded_channel();
+            let (downstream_tx, downstream_rx) = mpsc::unbounded_channel();
+            clients.insert(
+                client_id.clone(),
+                ClientStream {
+                    upstream_tx,
+                    downstream_rx,
+                    last_seen: Instant::now(),
+                },
+            );
+            self.spawn_bridge(client_id.clone(), upstream_rx, downstream_tx);
+        }
+
+        let Some(client) = clients.get_mut(&client_id) else {
+            return Vec::new();
+        };
+        client.last_seen = Instant::now();
+
+        if !payload.is_empty() {
+            let _ = client.upstream_tx.send(payload);
+        }
+
+        client.downstream_rx.try_recv().unwrap_or_default()
+    }
+
+    // Dials target_addr for a newly seen client id and relays bytes between
+    // the resulting TCP stream and the client's duplex channels until either
+    // side closes, then drops the client's entry.
+    fn spawn_bridge(
+        &self,
+        client_id: String,
+        mut upstream_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        downstream_tx: mpsc::UnboundedSender<Vec<u8>>,
+    ) {
+        let target_addr = self.target_addr;
+        let clients = self.clients.clone();
+
+        tokio::spawn(async move {
+            let mut socket = match TcpStream::connect(target_addr).await {
+                Ok(socket) => socket,
+                Err(_) => {
+                    clients.lock().await.remove(&client_id);
+                    return;
+                }
+            };
+
+            let mut buf = [0u8; 4096];
+            loop {
+                tokio::select! {
+                    result = socket.read(&mut buf) => {
+                        match result {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => {
+                                if downstream_tx.send(buf[..n].to_vec()).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    bytes = upstream_rx.recv() => {
+                        match bytes {
+                            Some(bytes) => {
+                                if socket.write_all(&bytes).await.is_err() {
+                                    break;
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                }
+            }
+
+            clients.lock().await.remove(&client_id);
+        });
+    }
+}
+
+// Client-side half of the DNS tunnel: dispatches the query names produced by
+// encode_query_names against the delegated DNS-tunnel server and reassembles
+// the TXT character-strings in each response back into a byte stream.
+pub struct DnsTunnelClient {
+    server_addr: SocketAddr,
+    suffix: Name,
+    client_id: String,
+}
+
+impl DnsTunnelClient {
+    pub fn new(server_addr: SocketAddr, suffix: Name, client_id: String) -> Self {
+        Self {
+            server_addr,
+            suffix,
+            client_id,
+        }
+    }
+
+    // Sends `data` to the tunnel server as one or more TXT queries, and
+    // returns the downstream bytes the server had queued for this client,
+    // reassembled across every response received.
+    pub async fn exchange(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(self.server_addr).await?;
+
+        let mut downstream = Vec::new();
+        for query_name in encode_query_names(&self.client_id, data, &self.suffix.to_string()) {
+            downstream.extend(self.send_query(&socket, &query_name).await?);
+        }
+        Ok(downstream)
+    }
+
+    async fn send_query(&self, socket: &UdpSocket, query_name: &str) -> io::Result<Vec<u8>> {
+        let name = Name::from_ascii(query_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut query = Query::new();
+        query.set_name(name).set_query_type(RecordType::TXT);
+
+        let mut message = Message::new();
+        message.set_id(TUNNEL_QUERY_ID.fetch_add(1, Ordering::Relaxed));
+        message.set_message_type(MessageType::Query);
+        message.set_op_code(OpCode::Query);
+        message.set_recursion_desired(true);
+        message.add_query(query);
+
+        let wire = message
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        socket.send(&wire).await?;
+
+        let mut buf = [0u8; 4096];
+        let n = socket.recv(&mut buf).await?;
+        let response = Message::from_vec(&buf[..n])
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let mut payload = Vec::new();
+        for record in response.answers() {
+            if let Some(RData::TXT(txt)) = record.data() {
+                let strings: Vec<String> = txt
+                    .txt_data()
+                    .iter()
+                    .map(|s| String::from_utf8_lossy(s).to_string())
+                    .collect();
+                if let Some(decoded) = decode_txt_strings(&strings) {
+                    payload.extend(decoded);
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for DnsTunnelServer {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let query = request.query();
+        let name: Name = query.name().into();
+
+        let mut header = Header::response_from_request(request.header());
+
+        if query.query_type() != RecordType::TXT {
+            header.set_response_code(ResponseCode::NotImp);
+            let response = MessageResponseBuilder::from_message_request(request)
+                .build_no_records(header);
+            return response_handle.send_response(response).await.unwrap_or_else(|_| header.into());
+        }
+
+        let downstream = self.handle_query(&name).await;
+        let txt = RData::TXT(TXT::new(encode_txt_strings(&downstream)));
+        let record = Record::from_rdata(name.clone(), 0, txt);
+
+        header.set_response_code(ResponseCode::NoError);
+        let response = MessageResponseBuilder::from_message_request(request).build(
+            header,
+            std::iter::once(&record),
+            std::iter::empty(),
+            std::iter::empty(),
+            std::iter::empty(),
+        );
+
+        response_handle
+            .send_response(response)
+            .await
+            .unwrap_or_else(|_| header.into())
+    }
+}
@@ -1,12 +1,25 @@
 mod dns_resolver;
+mod dns_tunnel;
+mod dnssec;
+mod proxy_protocol;
+mod tls;
 mod tunnel;
 mod utils;
 use crate::dns_resolver::DNSResolver;
+use crate::tunnel::Tunnel;
+use std::sync::Arc;
 use tokio;
 
 #[tokio::main]
 async fn main() {
     println!("Hello, world!");
 
-    let dns_resolver = DNSResolver::new(60);
+    let dns_resolver = Arc::new(DNSResolver::new(60));
+    let tunnel = Tunnel::new("127.0.0.1:8080", dns_resolver)
+        .await
+        .expect("failed to bind tunnel listener");
+
+    if let Err(e) = tunnel.run().await {
+        eprintln!("tunnel error: {}", e);
+    }
 }
@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+const VERSION_AND_COMMAND_PROXY: u8 = 0x21; // version 2, command PROXY
+const FAMILY_TCP_IPV4: u8 = 0x11; // AF_INET, SOCK_STREAM
+const FAMILY_TCP_IPV6: u8 = 0x21; // AF_INET6, SOCK_STREAM
+const FAMILY_UNSPEC: u8 = 0x00; // AF_UNSPEC, unknown protocol
+
+// Encode a PROXY protocol v2 header carrying `client_addr` as the source and
+// `dest_addr` as the destination, so a backend behind the tunnel can recover
+// the real client IP. Mixed IPv4/IPv6 pairs aren't representable in a single
+// TCP address block, so they fall back to the zero-length UNSPEC header that
+// PROXY protocol parsers must accept.
+pub fn encode_v2_header(client_addr: SocketAddr, dest_addr: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(28);
+    header.extend_from_slice(&SIGNATURE);
+    header.push(VERSION_AND_COMMAND_PROXY);
+
+    match (client_addr, dest_addr) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            header.push(FAMILY_TCP_IPV4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            header.push(FAMILY_TCP_IPV6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        _ => {
+            header.push(FAMILY_UNSPEC);
+            header.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_ipv4_header_with_signature_and_addresses() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let dest: SocketAddr = "198.51.100.9:443".parse().unwrap();
+
+        let header = encode_v2_header(client, dest);
+
+        assert_eq!(&header[..12], &SIGNATURE);
+        assert_eq!(header[12], VERSION_AND_COMMAND_PROXY);
+        assert_eq!(header[13], FAMILY_TCP_IPV4);
+        assert_eq!(&header[14..16], &12u16.to_be_bytes());
+        assert_eq!(&header[16..20], &[203, 0, 113, 7]);
+        assert_eq!(&header[20..24], &[198, 51, 100, 9]);
+        assert_eq!(&header[24..26], &51234u16.to_be_bytes());
+        assert_eq!(&header[26..28], &443u16.to_be_bytes());
+        assert_eq!(header.len(), 28);
+    }
+
+    #[test]
+    fn encodes_unspec_header_for_mixed_address_families() {
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let dest: SocketAddr = "[::1]:443".parse().unwrap();
+
+        let header = encode_v2_header(client, dest);
+
+        assert_eq!(header[13], FAMILY_UNSPEC);
+        assert_eq!(&header[14..16], &0u16.to_be_bytes());
+        assert_eq!(header.len(), 16);
+    }
+}
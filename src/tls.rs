@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+// Builds a TlsAcceptor from a PEM certificate chain and private key,
+// offering `alpn_protocols` during the handshake (in preference order) so a
+// client negotiating "h2" can be handed off to the HTTP/2 tunnel path.
+pub fn load_tls_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    alpn_protocols: &[String],
+) -> io::Result<TlsAcceptor> {
+    let cert_chain = load_certs(cert_path)?;
+    let private_key = load_private_key(key_path)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, private_key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|proto| proto.as_bytes().to_vec())
+        .collect();
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// Accepts the private key PEM formats actually seen in the wild: PKCS#8
+// ("BEGIN PRIVATE KEY"), PKCS#1/RSA ("BEGIN RSA PRIVATE KEY"), and SEC1/EC
+// ("BEGIN EC PRIVATE KEY"). `rustls_pemfile::read_one` walks the file once,
+// so items of any of these kinds are picked up regardless of order.
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(PrivateKey(key)),
+            Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no PKCS#8, PKCS#1, or SEC1 private key found in PEM file",
+                ))
+            }
+        }
+    }
+}
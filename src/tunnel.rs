@@ -1,32 +1,193 @@
+use crate::dns_resolver::DNSResolver;
+use crate::proxy_protocol;
 use crate::utils::TunnelType;
+use bytes::Bytes;
 use std::net::SocketAddr;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+// Per-connection candidate-dialing timeout; short enough that a dead IP
+// doesn't stall failover to the next one.
+const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// A CONNECT/raw-TCP destination before it has been resolved to concrete
+// socket addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetAddr {
+    Host(String, u16),
+    Addr(SocketAddr),
+}
 
 pub struct Tunnel {
     listener: TcpListener,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
 }
 
 impl Tunnel {
-    pub async fn new(address: &str) -> io::Result<Self> {
+    pub async fn new(address: &str, dns_resolver: Arc<DNSResolver>) -> io::Result<Self> {
+        Self::new_with_proxy_protocol(address, dns_resolver, false).await
+    }
+
+    pub async fn new_with_proxy_protocol(
+        address: &str,
+        dns_resolver: Arc<DNSResolver>,
+        send_proxy_protocol: bool,
+    ) -> io::Result<Self> {
+        Self::new_full(address, dns_resolver, send_proxy_protocol, None).await
+    }
+
+    // Accept `https://` CONNECT clients directly: `tls_acceptor` terminates
+    // TLS on every accepted socket, and a client that negotiates the "h2"
+    // ALPN protocol is handed to the HTTP/2 CONNECT path instead of the raw
+    // byte-relay used for HTTP/1.1.
+    pub async fn new_with_tls(
+        address: &str,
+        dns_resolver: Arc<DNSResolver>,
+        send_proxy_protocol: bool,
+        tls_acceptor: TlsAcceptor,
+    ) -> io::Result<Self> {
+        Self::new_full(address, dns_resolver, send_proxy_protocol, Some(Arc::new(tls_acceptor))).await
+    }
+
+    async fn new_full(
+        address: &str,
+        dns_resolver: Arc<DNSResolver>,
+        send_proxy_protocol: bool,
+        tls_acceptor: Option<Arc<TlsAcceptor>>,
+    ) -> io::Result<Self> {
         let listener = TcpListener::bind(address).await?;
-        Ok(Self { listener: listener })
+        Ok(Self {
+            listener: listener,
+            dns_resolver: dns_resolver,
+            send_proxy_protocol,
+            tls_acceptor,
+        })
     }
 
     pub async fn run(&self) -> io::Result<()> {
         loop {
-            let (socket, _) = self.listener.accept().await?;
-            tokio::spawn(async move {
-                if let Err(e) = handle_tcp(socket).await {
-                    eprintln!("failed to process connection; error = {}", e);
+            let (socket, client_addr) = self.listener.accept().await?;
+            let dns_resolver = self.dns_resolver.clone();
+            let send_proxy_protocol = self.send_proxy_protocol;
+
+            match self.tls_acceptor.clone() {
+                Some(acceptor) => {
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            handle_tls(socket, client_addr, acceptor, dns_resolver, send_proxy_protocol).await
+                        {
+                            eprintln!("failed to process TLS connection; error = {}", e);
+                        }
+                    });
+                }
+                None => {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_tcp(socket, client_addr, dns_resolver, send_proxy_protocol).await {
+                            eprintln!("failed to process connection; error = {}", e);
+                        }
+                    });
                 }
-            });
+            }
         }
     }
 }
 
-async fn handle_tcp(mut client_socket: TcpStream) -> io::Result<()> {
-    println!("new connection from {}", client_socket.peer_addr()?);
+// Resolve a TargetAddr to one or more candidate SocketAddrs, consulting the
+// DNSResolver cache for Host targets.
+async fn resolve_target(
+    target: &TargetAddr,
+    dns_resolver: &DNSResolver,
+) -> io::Result<Vec<SocketAddr>> {
+    match target {
+        TargetAddr::Addr(addr) => Ok(vec![*addr]),
+        TargetAddr::Host(host, port) => {
+            let ips = dns_resolver
+                .resolve_domain(host)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect())
+        }
+    }
+}
+
+// Try each resolved candidate in order, giving up on one after
+// CONNECT_ATTEMPT_TIMEOUT and falling through to the next, so the multi-IP
+// results cached by DNSResolver are actually used for failover. Returns the
+// candidate address actually connected to, alongside the stream.
+async fn connect_to_target(
+    target: &TargetAddr,
+    dns_resolver: &DNSResolver,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let candidates = resolve_target(target, dns_resolver).await?;
+
+    let mut last_err = None;
+    for candidate in candidates {
+        match tokio::time::timeout(CONNECT_ATTEMPT_TIMEOUT, TcpStream::connect(candidate)).await {
+            Ok(Ok(stream)) => return Ok((stream, candidate)),
+            Ok(Err(e)) => last_err = Some(e),
+            Err(_) => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connection attempt to {} timed out", candidate),
+                ))
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::AddrNotAvailable, "no candidate addresses to connect to")
+    }))
+}
+
+// Write a PROXY protocol v2 header carrying the original client address to
+// the already-connected upstream socket, so the backend can recover it.
+async fn write_proxy_protocol_header(
+    upstream: &mut TcpStream,
+    client_addr: SocketAddr,
+    dest_addr: SocketAddr,
+) -> io::Result<()> {
+    let header = proxy_protocol::encode_v2_header(client_addr, dest_addr);
+    upstream.write_all(&header).await
+}
+
+// Terminate TLS on a newly accepted socket and dispatch based on the
+// negotiated ALPN protocol: "h2" goes to the HTTP/2 CONNECT path, everything
+// else (including no ALPN) goes through the HTTP/1.1 CONNECT handler.
+async fn handle_tls(
+    socket: TcpStream,
+    client_addr: SocketAddr,
+    acceptor: Arc<TlsAcceptor>,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+) -> io::Result<()> {
+    let tls_stream = acceptor.accept(socket).await?;
+
+    let negotiated_h2 = tls_stream
+        .get_ref()
+        .1
+        .alpn_protocol()
+        .map(|proto| proto == b"h2")
+        .unwrap_or(false);
+
+    if negotiated_h2 {
+        handle_h2_connect(tls_stream, client_addr, dns_resolver, send_proxy_protocol).await
+    } else {
+        handle_http_connect(tls_stream, client_addr, dns_resolver, send_proxy_protocol).await
+    }
+}
+
+async fn handle_tcp(
+    mut client_socket: TcpStream,
+    client_addr: SocketAddr,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+) -> io::Result<()> {
+    println!("new connection from {}", client_addr);
 
     // 1024 byte buffer to read from tcp stream
     let mut buffer = [0; 1024];
@@ -43,13 +204,14 @@ async fn handle_tcp(mut client_socket: TcpStream) -> io::Result<()> {
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid address format"))?;
 
     println!("destination str: {}", destination);
-    let dest_server_addr: SocketAddr = destination
-        .parse()
+    let target = parse_target_addr(destination.trim())
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid address"))?;
 
-    println!("destination socket: {}", dest_server_addr);
+    let (mut dest_server_socket, dest_addr) = connect_to_target(&target, &dns_resolver).await?;
 
-    let mut dest_server_socket = TcpStream::connect(dest_server_addr).await?;
+    if send_proxy_protocol {
+        write_proxy_protocol_header(&mut dest_server_socket, client_addr, dest_addr).await?;
+    }
 
     // create a stream to client and to destination
     let (mut client_reader, mut client_writer) = client_socket.split();
@@ -69,18 +231,32 @@ async fn handle_tcp(mut client_socket: TcpStream) -> io::Result<()> {
     Ok(())
 }
 
-async fn handle_http_connect(mut client_socket: TcpStream) -> io::Result<()> {
+// Handles an HTTP/1.1 CONNECT request over any client transport (plaintext
+// TCP or a terminated TLS stream).
+async fn handle_http_connect<S>(
+    mut client_socket: S,
+    client_addr: SocketAddr,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // Read the HTTP CONNECT request
     let mut buffer = [0; 1024];
     let n = client_socket.read(&mut buffer).await?;
 
     // Parse the request to get the target address
     let request_str = std::str::from_utf8(&buffer[..n]).unwrap();
-    let target_address = parse_http_connect_request(request_str)
+    let target = parse_http_connect_request(request_str)
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP CONNECT request"))?;
 
-    // Connect to the target server
-    let mut target_socket = TcpStream::connect(target_address).await?;
+    // Connect to the target server, resolving host targets through the DNSResolver
+    let (mut target_socket, dest_addr) = connect_to_target(&target, &dns_resolver).await?;
+
+    if send_proxy_protocol {
+        write_proxy_protocol_header(&mut target_socket, client_addr, dest_addr).await?;
+    }
 
     // Send successful response back to the client
     client_socket.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
@@ -89,18 +265,136 @@ async fn handle_http_connect(mut client_socket: TcpStream) -> io::Result<()> {
     relay_data(client_socket, target_socket).await
 }
 
+// Handles a CONNECT tunnel multiplexed over a single HTTP/2 connection: each
+// h2 stream whose method is CONNECT gets its own target connection and byte
+// relay, per the h2 CONNECT-proxy example.
+async fn handle_h2_connect<S>(
+    io: S,
+    client_addr: SocketAddr,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut connection = h2::server::handshake(io)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    while let Some(result) = connection.accept().await {
+        let (request, respond) = result.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let dns_resolver = dns_resolver.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_h2_stream(request, respond, client_addr, dns_resolver, send_proxy_protocol).await
+            {
+                eprintln!("h2 CONNECT stream failed; error = {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_h2_stream(
+    request: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    client_addr: SocketAddr,
+    dns_resolver: Arc<DNSResolver>,
+    send_proxy_protocol: bool,
+) -> io::Result<()> {
+    if request.method() != http::Method::CONNECT {
+        let response = http::Response::builder()
+            .status(http::StatusCode::METHOD_NOT_ALLOWED)
+            .body(())
+            .unwrap();
+        let _ = respond.send_response(response, true);
+        return Ok(());
+    }
+
+    let authority = request
+        .uri()
+        .authority()
+        .map(|authority| authority.to_string())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "CONNECT request missing authority"))?;
+
+    let target = parse_target_addr(&authority)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid CONNECT authority"))?;
+
+    let (mut target_socket, dest_addr) = connect_to_target(&target, &dns_resolver).await?;
+
+    if send_proxy_protocol {
+        write_proxy_protocol_header(&mut target_socket, client_addr, dest_addr).await?;
+    }
+
+    let response = http::Response::builder().status(http::StatusCode::OK).body(()).unwrap();
+    let mut send_stream = respond
+        .send_response(response, false)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let mut recv_stream = request.into_body();
+    let (mut target_reader, mut target_writer) = target_socket.split();
+
+    let upstream_to_h2 = async {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = target_reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            send_stream
+                .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+        let _ = send_stream.send_data(Bytes::new(), true);
+        Ok::<(), io::Error>(())
+    };
+
+    let h2_to_upstream = async {
+        while let Some(chunk) = recv_stream.data().await {
+            let chunk = chunk.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let _ = recv_stream.flow_control().release_capacity(chunk.len());
+            target_writer.write_all(&chunk).await?;
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    tokio::select! {
+        result = upstream_to_h2 => result?,
+        result = h2_to_upstream => result?,
+    }
+
+    Ok(())
+}
+
 // Function to parse the HTTP CONNECT request and extract the target address
-fn parse_http_connect_request(request: &str) -> Result<String, ()> {
-    request.lines()
+fn parse_http_connect_request(request: &str) -> Result<TargetAddr, ()> {
+    request
+        .lines()
         .next()
         .and_then(|line| line.split_whitespace().nth(1))
-        .map(|addr| addr.to_string())
         .ok_or(())
+        .and_then(parse_target_addr)
+}
+
+// Parse a "host:port" string into a TargetAddr, preferring the Addr variant
+// when the host portion is already a literal IP.
+fn parse_target_addr(addr: &str) -> Result<TargetAddr, ()> {
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return Ok(TargetAddr::Addr(socket_addr));
+    }
+
+    let (host, port) = addr.rsplit_once(':').ok_or(())?;
+    let port: u16 = port.parse().map_err(|_| ())?;
+    Ok(TargetAddr::Host(host.to_string(), port))
 }
 
 // Function to relay data between client and target server
-async fn relay_data(mut client_socket: TcpStream, mut target_socket: TcpStream) -> io::Result<()> {
-    let (mut client_reader, mut client_writer) = client_socket.split();
+async fn relay_data<S>(client_socket: S, mut target_socket: TcpStream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client_socket);
     let (mut server_reader, mut server_writer) = target_socket.split();
 
     let client_to_server = io::copy(&mut client_reader, &mut server_writer);
@@ -150,7 +444,8 @@ mod tests {
 
         // Start the tunnel
         let tunnel_addr_str = "127.0.0.1:4444";
-        let tunnel = Tunnel::new(tunnel_addr_str).await?;
+        let dns_resolver = Arc::new(DNSResolver::new(60));
+        let tunnel = Tunnel::new(tunnel_addr_str, dns_resolver).await?;
         tokio::spawn(async move {
             let _ = tunnel.run().await;
         });
@@ -172,4 +467,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_connect_to_target_fails_over_to_next_candidate() -> io::Result<()> {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        // Start a mock server to act as the second (reachable) candidate.
+        let server_addr = start_mock_server().await?;
+
+        // 127.0.0.2 is loopback but nothing listens there, so connecting to
+        // it fails fast (connection refused) rather than hanging, letting
+        // connect_to_target fall through to the real candidate.
+        let domain = "multi-candidate.test";
+        let dns_resolver = DNSResolver::new(60);
+        dns_resolver.update_cache(domain, vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), server_addr.ip()]);
+
+        let target = TargetAddr::Host(domain.to_string(), server_addr.port());
+        let (mut stream, connected_addr) = connect_to_target(&target, &dns_resolver).await?;
+
+        assert_eq!(
+            connected_addr, server_addr,
+            "should have failed over past the dead candidate to the reachable one"
+        );
+
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        Ok(())
+    }
 }
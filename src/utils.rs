@@ -0,0 +1,35 @@
+pub struct CLIConfig {
+    pub name: String,
+    pub proxy_address: String,
+    pub destination_address: String,
+    pub client_address: String,
+    // When set, a PROXY protocol v2 header carrying the real client address
+    // is written to the upstream socket before relaying begins.
+    pub send_proxy_protocol: bool,
+    // Paths to a PEM certificate chain and private key; when both are set,
+    // the tunnel accepts `https://` CONNECT clients over TLS instead of
+    // plaintext TCP.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // ALPN protocols offered during the TLS handshake, e.g. ["h2", "http/1.1"].
+    pub tls_alpn_protocols: Vec<String>,
+}
+
+// connection result
+pub enum TunnelConnectionResult {
+    Ok, // 200
+    BadRequest, // 400
+    Unauthorized, // 401
+    Forbidden, // 403
+    NotFound, // 404
+    RequestTimeout, // 408
+    InternalServerError, // 500
+    BadGateway, // 502
+    Error,
+}
+
+// which handler a connection gets dispatched to
+pub enum TunnelType {
+    Tcp,
+    HttpConnect,
+}